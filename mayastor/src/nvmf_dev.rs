@@ -11,16 +11,51 @@ use snafu::{ResultExt, Snafu};
 use spdk_sys::{
     spdk_bdev_nvme_create,
     spdk_bdev_nvme_delete,
+    spdk_nvme_ctrlr_cmd_get_log_page,
+    spdk_nvme_ctrlr_process_admin_completions,
+    spdk_nvme_detach,
+    spdk_nvme_probe,
+    spdk_nvme_transport_id,
+    spdk_nvmf_discovery_log_page,
+    spdk_nvmf_discovery_log_page_entry,
+    spdk_poller_register_named,
+    spdk_poller_unregister,
+    SPDK_NVME_TRANSPORT_RDMA,
     SPDK_NVME_TRANSPORT_TCP,
     SPDK_NVMF_ADRFAM_IPV4,
+    SPDK_NVMF_ADRFAM_IPV6,
 };
-use std::{convert::TryFrom, ffi::CString, os::raw::c_void};
+use std::{convert::TryFrom, ffi::CString, mem::size_of, os::raw::c_void};
 use url::Url;
 
 #[derive(Debug, Snafu)]
 pub enum ParseError {
     #[snafu(display("Missing path component"))]
     PathMissing {},
+    #[snafu(display(
+        "host {} is an IPv6 address but adrfam was explicitly set to ipv4",
+        host
+    ))]
+    AdrfamMismatch { host: String },
+}
+
+/// well known subnqn used to address a remote discovery controller, see
+/// NVMe-oF 1.1 section 4.2
+pub const DISCOVERY_NQN: &str = "nqn.2014-08.org.nvmexpress.discovery";
+
+/// NVMe Get Log Page identifier for the Discovery Log Page, see NVMe-oF 1.1
+/// section 5.3
+const NVME_LOG_DISCOVERY_PAGE: u8 = 0x70;
+
+/// A single entry of the Discovery Log Page, describing one I/O controller
+/// advertised by the remote discovery service.
+#[derive(Debug, Clone)]
+pub struct DiscoveryLogEntry {
+    pub trtype: String,
+    pub adrfam: String,
+    pub traddr: String,
+    pub trsvcid: String,
+    pub subnqn: String,
 }
 
 /// nvme_bdev create arguments, ideally you should not use this directly but use
@@ -29,7 +64,7 @@ pub enum ParseError {
 pub struct NvmfBdev {
     /// name of the bdev that should be created
     pub name: String,
-    /// transport type (only TCP for now)
+    /// transport type, either TCP (default) or RDMA
     pub trtype: String,
     /// the addres family either ipv4 or ipv6
     pub adrfam: String,
@@ -50,6 +85,9 @@ pub struct NvmfBdev {
     pub prchk_reftag: bool,
     /// Enable protection information checking of the Application Tag    field
     pub prchk_guard: bool,
+    /// when set, connect to a discovery controller instead of a single I/O
+    /// controller and create a bdev for every subsystem it advertises
+    pub discover: bool,
 }
 
 impl NvmfBdev {
@@ -127,6 +165,268 @@ impl NvmfBdev {
         })
     }
 
+    /// true if this describes a connection to a discovery controller rather
+    /// than a single I/O controller
+    pub fn is_discovery(&self) -> bool {
+        self.discover || self.subnqn == DISCOVERY_NQN
+    }
+
+    /// probe and attach to the discovery controller named by this struct,
+    /// returning the raw ctrlr pointer once the fabric connect sequence
+    /// completes
+    async fn attach_discovery_ctrlr(
+        &self,
+    ) -> Result<*mut spdk_sys::spdk_nvme_ctrlr, BdevError> {
+        let mut ctx = NvmeCreateCtx::new(self);
+        ctx.transport_id.subnqn = [0i8; 256];
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                DISCOVERY_NQN.as_ptr() as *const _ as *mut libc::c_void,
+                &mut ctx.transport_id.subnqn[0] as *const _ as *mut libc::c_void,
+                DISCOVERY_NQN.len(),
+            );
+        }
+
+        let (sender, receiver) =
+            oneshot::channel::<*mut spdk_sys::spdk_nvme_ctrlr>();
+
+        let rc = unsafe {
+            spdk_nvme_probe(
+                &ctx.transport_id,
+                cb_arg(sender),
+                Some(NvmfBdev::discovery_probe_cb),
+                Some(NvmfBdev::discovery_attach_cb),
+                None,
+            )
+        };
+
+        errno_result_from_i32((), rc).context(nexus_uri::InvalidParams {
+            name: self.name.clone(),
+        })?;
+
+        let ctrlr = receiver.await.expect("Cancellation is not supported");
+        if ctrlr.is_null() {
+            return Err(BdevError::BdevNotFound {
+                name: self.name.clone(),
+            });
+        }
+
+        Ok(ctrlr)
+    }
+
+    unsafe extern "C" fn discovery_probe_cb(
+        _cb_ctx: *mut c_void,
+        _trid: *const spdk_nvme_transport_id,
+        _opts: *mut spdk_sys::spdk_nvme_ctrlr_opts,
+    ) -> bool {
+        // always connect, we only ever probe the single discovery trid we
+        // were given
+        true
+    }
+
+    unsafe extern "C" fn discovery_attach_cb(
+        cb_ctx: *mut c_void,
+        _trid: *const spdk_nvme_transport_id,
+        ctrlr: *mut spdk_sys::spdk_nvme_ctrlr,
+        _opts: *const spdk_sys::spdk_nvme_ctrlr_opts,
+    ) {
+        let sender = Box::from_raw(
+            cb_ctx as *mut oneshot::Sender<*mut spdk_sys::spdk_nvme_ctrlr>,
+        );
+        let _ = sender.send(ctrlr);
+    }
+
+    /// fetch the Discovery Log Page (log id 0x70) from an attached discovery
+    /// controller, decoding each record into a [`DiscoveryLogEntry`]. If
+    /// the generation counter (`genctr`) changes between reading the header
+    /// and reading the records (i.e. the remote changed its set of
+    /// subsystems, so `numrec` may be stale) the header is re-read and the
+    /// records reissued.
+    async fn read_discovery_log_page(
+        ctrlr: *mut spdk_sys::spdk_nvme_ctrlr,
+    ) -> Result<Vec<DiscoveryLogEntry>, BdevError> {
+        loop {
+            let header = Self::get_log_page::<spdk_nvmf_discovery_log_page>(
+                ctrlr,
+                size_of::<spdk_nvmf_discovery_log_page>() as u32,
+                0,
+            )
+            .await?;
+
+            let numrec = header.numrec as usize;
+            let entries_size = numrec
+                * size_of::<spdk_nvmf_discovery_log_page_entry>();
+            if numrec == 0 {
+                return Ok(Vec::new());
+            }
+
+            let page = Self::get_log_page_raw(
+                ctrlr,
+                (size_of::<spdk_nvmf_discovery_log_page>() + entries_size)
+                    as u32,
+                0,
+            )
+            .await?;
+
+            // the generation counter changed while we were reading, meaning
+            // the set of records may no longer match `numrec` -- start over
+            let refreshed: &spdk_nvmf_discovery_log_page =
+                unsafe { &*(page.as_ptr() as *const _) };
+            if refreshed.genctr != header.genctr {
+                continue;
+            }
+
+            let entries_ptr = unsafe {
+                page.as_ptr()
+                    .add(size_of::<spdk_nvmf_discovery_log_page>())
+                    as *const spdk_nvmf_discovery_log_page_entry
+            };
+
+            let mut out = Vec::with_capacity(numrec);
+            for i in 0 .. numrec {
+                let entry = unsafe { &*entries_ptr.add(i) };
+                out.push(DiscoveryLogEntry {
+                    trtype: trtype_to_string(entry.trtype),
+                    adrfam: adrfam_to_string(entry.adrfam),
+                    traddr: cbuf_to_string(&entry.traddr),
+                    trsvcid: cbuf_to_string(&entry.trsvcid),
+                    subnqn: cbuf_to_string(&entry.subnqn),
+                });
+            }
+
+            return Ok(out);
+        }
+    }
+
+    /// issue a Get Log Page admin command and return the raw payload once
+    /// the completion callback fires
+    async fn get_log_page_raw(
+        ctrlr: *mut spdk_sys::spdk_nvme_ctrlr,
+        payload_size: u32,
+        offset: u64,
+    ) -> Result<Vec<u8>, BdevError> {
+        let mut payload = vec![0u8; payload_size as usize];
+        let (sender, receiver) = oneshot::channel::<ErrnoResult<()>>();
+
+        let rc = unsafe {
+            spdk_nvme_ctrlr_cmd_get_log_page(
+                ctrlr,
+                NVME_LOG_DISCOVERY_PAGE,
+                0,
+                payload.as_mut_ptr() as *mut c_void,
+                payload_size,
+                offset,
+                Some(NvmfBdev::log_page_done),
+                cb_arg(sender),
+            )
+        };
+
+        errno_result_from_i32((), rc).context(nexus_uri::InvalidParams {
+            name: "discovery-log-page".to_string(),
+        })?;
+
+        // Nothing else polls this transient discovery controller's admin
+        // queue, so register a short-lived poller for the lifetime of this
+        // one command instead of busy-spinning the reactor thread waiting
+        // on it. The receiver suspends the task like every other callback
+        // in this file; the poller's only job is to keep driving
+        // completions in the background until it fires.
+        let poller = unsafe {
+            spdk_poller_register_named(
+                Some(Self::drive_admin_completions),
+                ctrlr as *mut c_void,
+                0,
+                "discovery_admin_poll\0" as *const _ as *mut _,
+            )
+        };
+
+        let result = receiver.await.expect("Cancellation is not supported");
+
+        let mut poller = poller;
+        unsafe { spdk_poller_unregister(&mut poller) };
+
+        result.context(nexus_uri::CreateBdev {
+            name: "discovery-log-page".to_string(),
+        })?;
+
+        Ok(payload)
+    }
+
+    extern "C" fn drive_admin_completions(ctx: *mut c_void) -> i32 {
+        unsafe {
+            spdk_nvme_ctrlr_process_admin_completions(
+                ctx as *mut spdk_sys::spdk_nvme_ctrlr,
+            )
+        }
+    }
+
+    async fn get_log_page<T>(
+        ctrlr: *mut spdk_sys::spdk_nvme_ctrlr,
+        payload_size: u32,
+        offset: u64,
+    ) -> Result<T, BdevError>
+    where
+        T: Copy,
+    {
+        let raw = Self::get_log_page_raw(ctrlr, payload_size, offset).await?;
+        Ok(unsafe { *(raw.as_ptr() as *const T) })
+    }
+
+    unsafe extern "C" fn log_page_done(
+        ctx: *mut c_void,
+        cpl: *const spdk_sys::spdk_nvme_cpl,
+    ) {
+        let sender =
+            Box::from_raw(ctx as *mut oneshot::Sender<ErrnoResult<()>>);
+        let status = unsafe { &(*cpl).status };
+        let rc = if status.sc() == 0 && status.sct() == 0 {
+            0
+        } else {
+            -(libc::EIO)
+        };
+        let _ = sender.send(errno_result_from_i32((), rc));
+    }
+
+    /// connect to the discovery controller named by this struct, enumerate
+    /// every I/O controller it advertises and create a bdev for each one,
+    /// analogous to the `connect-all` flow. Subsystems that already have a
+    /// bdev (looked up by the name we would otherwise create) are skipped.
+    pub async fn discover(self) -> Result<Vec<String>, BdevError> {
+        let ctrlr = self.attach_discovery_ctrlr().await?;
+        let entries = Self::read_discovery_log_page(ctrlr).await;
+        unsafe { spdk_nvme_detach(ctrlr) };
+        let entries = entries?;
+
+        let mut bdevs = Vec::new();
+        for entry in entries {
+            let name = discovered_bdev_name(&entry);
+
+            if bdev_lookup_by_name(&name).is_some() {
+                bdevs.push(name);
+                continue;
+            }
+
+            let child = NvmfBdev {
+                name: name.clone(),
+                trtype: entry.trtype,
+                adrfam: entry.adrfam,
+                traddr: entry.traddr,
+                trsvcid: entry.trsvcid,
+                subnqn: entry.subnqn,
+                hostnqn: self.hostnqn.clone(),
+                hostaddr: self.hostaddr.clone(),
+                hostsvcid: self.hostsvcid.clone(),
+                prchk_reftag: self.prchk_reftag,
+                prchk_guard: self.prchk_guard,
+                discover: false,
+            };
+
+            bdevs.push(child.create().await?);
+        }
+
+        Ok(bdevs)
+    }
+
     /// destroy nvme bdev
     pub fn destroy(self, bdev_name: &str) -> Result<(), BdevError> {
         if bdev_lookup_by_name(bdev_name).is_none() {
@@ -143,6 +443,55 @@ impl NvmfBdev {
     }
 }
 
+/// converts a fixed-size, NUL or space padded byte buffer from a discovery
+/// log page entry into a trimmed `String`
+fn cbuf_to_string(buf: &[u8]) -> String {
+    let end = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[.. end]).trim().to_string()
+}
+
+fn trtype_to_string(trtype: u8) -> String {
+    match trtype as u32 {
+        spdk_sys::SPDK_NVMF_TRTYPE_RDMA => "RDMA",
+        spdk_sys::SPDK_NVMF_TRTYPE_TCP => "TCP",
+        spdk_sys::SPDK_NVMF_TRTYPE_FC => "FC",
+        _ => "TCP",
+    }
+    .to_string()
+}
+
+fn adrfam_to_string(adrfam: u8) -> String {
+    match adrfam as u32 {
+        spdk_sys::SPDK_NVMF_ADRFAM_IPV4 => "IPv4",
+        spdk_sys::SPDK_NVMF_ADRFAM_IPV6 => "IPv6",
+        _ => "IPv4",
+    }
+    .to_string()
+}
+
+/// Build the bdev name for a discovered subsystem the same way
+/// `TryFrom<&Url>` would for the equivalent URL, bracketing an IPv6
+/// `traddr`. Using the same format here is what lets `bdev_lookup_by_name`
+/// correctly dedup against a bdev that was created from a hand-specified
+/// URL for the same host.
+fn discovered_bdev_name(entry: &DiscoveryLogEntry) -> String {
+    // mirrors `TryFrom<&Url>`'s scheme selection: `rdma://` for RDMA,
+    // `nvmf://` (TCP) otherwise
+    let scheme = if entry.trtype == "RDMA" { "rdma" } else { "nvmf" };
+
+    if entry.adrfam == "IPv6" {
+        format!(
+            "{}://[{}]:{}/{}",
+            scheme, entry.traddr, entry.trsvcid, entry.subnqn
+        )
+    } else {
+        format!(
+            "{}://{}:{}/{}",
+            scheme, entry.traddr, entry.trsvcid, entry.subnqn
+        )
+    }
+}
+
 /// converts a nvmf URL to NVMF args
 impl TryFrom<&Url> for NvmfBdev {
     type Error = ParseError;
@@ -150,9 +499,24 @@ impl TryFrom<&Url> for NvmfBdev {
     fn try_from(u: &Url) -> std::result::Result<Self, Self::Error> {
         let mut n = NvmfBdev::default();
 
-        // defaults we currently only support
-        n.trtype = "TCP".into();
-        n.adrfam = "IPv4".into();
+        // the scheme selects the transport, e.g. `nvmf://` (TCP, the
+        // default) or `rdma://` to address an RDMA fabric; either can be
+        // overridden with a `?trtype=` query parameter below
+        n.trtype = match u.scheme() {
+            "rdma" => "RDMA".into(),
+            _ => "TCP".into(),
+        };
+
+        // a bracketed IPv6 literal host implies adrfam=ipv6, overridable
+        // with a `?adrfam=` query parameter below
+        let host = u.host();
+        let is_ipv6_host = matches!(host, Some(url::Host::Ipv6(_)));
+        n.adrfam = if is_ipv6_host {
+            "IPv6".into()
+        } else {
+            "IPv4".into()
+        };
+
         n.subnqn = match u
             .path_segments()
             .map(std::iter::Iterator::collect::<Vec<_>>)
@@ -167,7 +531,13 @@ impl TryFrom<&Url> for NvmfBdev {
             None => "4420".to_owned(),
         };
 
-        n.traddr = u.host_str().unwrap().to_string();
+        // `host_str()` keeps the `[...]` brackets url uses to serialize an
+        // IPv6 literal, but SPDK's `traddr` wants the bare address -- take
+        // it straight from the parsed `Host::Ipv6` instead
+        n.traddr = match host {
+            Some(url::Host::Ipv6(addr)) => addr.to_string(),
+            _ => u.host_str().unwrap().to_string(),
+        };
         n.name = u.to_string();
         let qp = u.query_pairs();
 
@@ -180,9 +550,36 @@ impl TryFrom<&Url> for NvmfBdev {
                 // PI guard for IO -- 512 + 8
                 // see nvme spec 1.3+ sec 8.3
                 "guard" => n.prchk_guard = true,
+                // explicitly request discovery mode regardless of subnqn
+                "discover" => n.discover = i.1.parse().unwrap_or(false),
+                // explicit transport selection, overrides the scheme
+                "trtype" => match i.1.to_lowercase().as_str() {
+                    "rdma" => n.trtype = "RDMA".into(),
+                    "tcp" => n.trtype = "TCP".into(),
+                    other => warn!("unsupported trtype {} ignored", other),
+                },
+                // explicit address family selection, overrides the host
+                "adrfam" => match i.1.to_lowercase().as_str() {
+                    "ipv6" => n.adrfam = "IPv6".into(),
+                    "ipv4" => n.adrfam = "IPv4".into(),
+                    other => warn!("unsupported adrfam {} ignored", other),
+                },
                 _ => warn!("query parameter {} ignored", i.0),
             }
         }
+
+        // the well known discovery subnqn always implies discovery mode
+        if n.subnqn == DISCOVERY_NQN {
+            n.discover = true;
+        }
+
+        // an IPv6 literal host can never be reached over adrfam=ipv4
+        if is_ipv6_host && n.adrfam == "IPv4" {
+            return Err(ParseError::AdrfamMismatch {
+                host: n.traddr.clone(),
+            });
+        }
+
         Ok(n)
     }
 }
@@ -253,9 +650,14 @@ impl NvmeCreateCtx {
             );
         }
 
-        // we can not test RDMA nor IPv6 at the moment
-        transport.trtype = SPDK_NVME_TRANSPORT_TCP;
-        transport.adrfam = SPDK_NVMF_ADRFAM_IPV4;
+        transport.trtype = match args.trtype.as_str() {
+            "RDMA" => SPDK_NVME_TRANSPORT_RDMA,
+            _ => SPDK_NVME_TRANSPORT_TCP,
+        };
+        transport.adrfam = match args.adrfam.as_str() {
+            "IPv6" => SPDK_NVMF_ADRFAM_IPV6,
+            _ => SPDK_NVMF_ADRFAM_IPV4,
+        };
 
         // the following parameters are optional, but we should fill them in to
         // get a proper topo mapping of the whole thing as soon as we
@@ -288,4 +690,129 @@ impl NvmeCreateCtx {
             names: [std::ptr::null_mut() as *mut libc::c_char; MAX_NAMESPACES],
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv6_url_yields_bare_traddr_and_adrfam_ipv6() {
+        let u = Url::parse(
+            "nvmf://[2001:db8::1]:4420/nqn.2019-05.io.openebs:disk0",
+        )
+        .unwrap();
+        let n = NvmfBdev::try_from(&u).unwrap();
+
+        assert_eq!(n.adrfam, "IPv6");
+        // must be the bare address, not `host_str()`'s bracketed form
+        assert_eq!(n.traddr, "2001:db8::1");
+    }
+
+    #[test]
+    fn ipv4_url_still_parses_as_before() {
+        let u = Url::parse("nvmf://10.0.0.1:4420/nqn.2019-05.io.openebs:disk0")
+            .unwrap();
+        let n = NvmfBdev::try_from(&u).unwrap();
+
+        assert_eq!(n.adrfam, "IPv4");
+        assert_eq!(n.traddr, "10.0.0.1");
+    }
+
+    #[test]
+    fn ipv6_host_rejects_explicit_adrfam_ipv4() {
+        let u = Url::parse(
+            "nvmf://[2001:db8::1]:4420/nqn.2019-05.io.openebs:disk0?adrfam=ipv4",
+        )
+        .unwrap();
+
+        assert!(matches!(
+            NvmfBdev::try_from(&u),
+            Err(ParseError::AdrfamMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn rdma_scheme_selects_rdma_transport() {
+        let u = Url::parse("rdma://10.0.0.1:4420/nqn.2019-05.io.openebs:disk0")
+            .unwrap();
+        let n = NvmfBdev::try_from(&u).unwrap();
+
+        assert_eq!(n.trtype, "RDMA");
+    }
+
+    #[test]
+    fn trtype_query_overrides_scheme() {
+        let u = Url::parse(
+            "nvmf://10.0.0.1:4420/nqn.2019-05.io.openebs:disk0?trtype=rdma",
+        )
+        .unwrap();
+        let n = NvmfBdev::try_from(&u).unwrap();
+
+        assert_eq!(n.trtype, "RDMA");
+    }
+
+    #[test]
+    fn discovery_subnqn_implies_discover_mode() {
+        let u = Url::parse(&format!("nvmf://10.0.0.1:4420/{}", DISCOVERY_NQN))
+            .unwrap();
+        let n = NvmfBdev::try_from(&u).unwrap();
+
+        assert!(n.is_discovery());
+    }
+
+    #[test]
+    fn discovered_ipv6_name_matches_url_parsed_name() {
+        let subnqn = "nqn.2019-05.io.openebs:disk0";
+        let entry = DiscoveryLogEntry {
+            trtype: "TCP".to_string(),
+            adrfam: "IPv6".to_string(),
+            traddr: "2001:db8::1".to_string(),
+            trsvcid: "4420".to_string(),
+            subnqn: subnqn.to_string(),
+        };
+
+        let u =
+            Url::parse(&format!("nvmf://[2001:db8::1]:4420/{}", subnqn))
+                .unwrap();
+        let from_url = NvmfBdev::try_from(&u).unwrap();
+
+        // the name `discover()` builds for an entry must dedup-match the
+        // bdev a hand-specified URL for the same host would produce
+        assert_eq!(discovered_bdev_name(&entry), from_url.name);
+    }
+
+    #[test]
+    fn discovered_ipv4_name_has_no_brackets() {
+        let entry = DiscoveryLogEntry {
+            trtype: "TCP".to_string(),
+            adrfam: "IPv4".to_string(),
+            traddr: "10.0.0.1".to_string(),
+            trsvcid: "4420".to_string(),
+            subnqn: "nqn.2019-05.io.openebs:disk0".to_string(),
+        };
+
+        assert_eq!(
+            discovered_bdev_name(&entry),
+            "nvmf://10.0.0.1:4420/nqn.2019-05.io.openebs:disk0"
+        );
+    }
+
+    #[test]
+    fn discovered_rdma_name_matches_url_parsed_name() {
+        let subnqn = "nqn.2019-05.io.openebs:disk0";
+        let entry = DiscoveryLogEntry {
+            trtype: "RDMA".to_string(),
+            adrfam: "IPv4".to_string(),
+            traddr: "10.0.0.1".to_string(),
+            trsvcid: "4420".to_string(),
+            subnqn: subnqn.to_string(),
+        };
+
+        let u =
+            Url::parse(&format!("rdma://10.0.0.1:4420/{}", subnqn)).unwrap();
+        let from_url = NvmfBdev::try_from(&u).unwrap();
+
+        assert_eq!(discovered_bdev_name(&entry), from_url.name);
+    }
 }
\ No newline at end of file